@@ -6,11 +6,17 @@ use crate::{
     value_type::ValueType,
     vocabularies::{
         applicator::{AllOf, Properties},
-        references::Ref,
+        references::{DynamicRef, Ref},
         validation::{MaxLength, Maximum, MinProperties, Type},
         Keyword,
     },
 };
+use petgraph::{
+    algo::tarjan_scc,
+    graph::{Graph, NodeIndex},
+    visit::{Bfs, EdgeRef},
+    Directed,
+};
 use serde_json::{Map, Value};
 use std::collections::{hash_map::Entry, HashMap, VecDeque};
 
@@ -63,66 +69,6 @@ impl From<&String> for EdgeLabel {
     }
 }
 
-/// Unique identifier of a node in a graph.
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
-pub(crate) struct NodeId(usize);
-
-impl NodeId {
-    pub(crate) fn value(&self) -> usize {
-        self.0
-    }
-    /// If this `NodeId` points to the root node.
-    pub(crate) fn is_root(&self) -> bool {
-        self.value() == 0
-    }
-}
-
-/// An edge between two JSON values stored in adjacency list.
-///
-/// # Example
-///
-/// JSON:
-///
-/// ```json
-/// {
-///     "properties": {
-///         "A": {
-///             "type": "object"
-///         },
-///         "B": {
-///             "type": "string"
-///         }
-///     }
-/// }
-/// ```
-///
-/// ("A", 1) - an edge between `<properties>` and `<type: object>`
-/// ("B", 2) - an edge between `<properties>` and `<type: string>`
-///
-/// ```text
-///   Nodes                      Edges
-///
-/// [                         [
-///   0 <properties>            [("A", 1), ("B", 2)]
-///   1 <type: object>          []
-///   2 <type: string>          []
-/// ]                         ]
-/// ```
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
-pub(crate) struct Edge {
-    pub(crate) label: EdgeLabel,
-    pub(crate) target: NodeId,
-}
-
-impl Edge {
-    pub(crate) fn new(label: impl Into<EdgeLabel>, target: NodeId) -> Edge {
-        Edge {
-            label: label.into(),
-            target,
-        }
-    }
-}
-
 /// An edge between a single JSON value and a range of JSON values that are stored contiguously.
 ///
 /// # Example
@@ -176,41 +122,46 @@ impl RangedEdge {
     }
 }
 
-/// A slot for a node in a tree.
-pub(crate) struct NodeSlot {
-    /// Unique node identifier.
-    id: NodeId,
-    /// Whether this slot was already used or not.
-    state: SlotState,
-}
-
-#[derive(Debug, Eq, PartialEq)]
-enum SlotState {
-    /// Slot was not previously used.
-    New,
-    /// Slot is already used.
-    Used,
+/// A compact set of small, densely-packed indices backed by `u64` words.
+///
+/// Used instead of `Vec<bool>` for per-node flags that are produced by graph
+/// traversals (e.g. which nodes participate in a cycle).
+#[derive(Debug, Clone)]
+pub(crate) struct BitVector {
+    data: Vec<u64>,
 }
 
-impl NodeSlot {
-    fn seen(id: NodeId) -> Self {
+impl BitVector {
+    /// Create a bit vector with enough capacity to hold `bits` indices without reallocating.
+    pub(crate) fn with_capacity(bits: usize) -> Self {
         Self {
-            id,
-            state: SlotState::Used,
+            data: vec![0; (bits + 63) / 64],
         }
     }
-    fn new(id: NodeId) -> Self {
-        Self {
-            id,
-            state: SlotState::New,
+
+    fn word_for(&mut self, bit: usize) -> usize {
+        let word = bit / 64;
+        if word >= self.data.len() {
+            self.data.resize(word + 1, 0);
         }
+        word
     }
-    fn is_new(&self) -> bool {
-        self.state == SlotState::New
+
+    /// Insert `bit` into the set, returning whether it was not already present.
+    pub(crate) fn insert(&mut self, bit: usize) -> bool {
+        let word = self.word_for(bit);
+        let mask = 1u64 << (bit % 64);
+        let changed = self.data[word] & mask == 0;
+        self.data[word] |= mask;
+        changed
     }
-}
 
-pub(crate) type VisitedMap = HashMap<*const Value, NodeId>;
+    /// Whether `bit` is present in the set.
+    pub(crate) fn contains(&self, bit: usize) -> bool {
+        let word = bit / 64;
+        word < self.data.len() && self.data[word] & (1u64 << (bit % 64)) != 0
+    }
+}
 
 /// Build a packed graph to represent JSON Schema.
 pub(crate) fn build<'s>(
@@ -218,153 +169,224 @@ pub(crate) fn build<'s>(
     root: &'s Resolver,
     resolvers: &'s HashMap<&str, Resolver>,
 ) -> Result<CompressedRangeGraph> {
-    // Convert `Value` to an adjacency list and add all remote nodes reachable from the root
-    let adjacency_list = AdjacencyList::new(schema, root, resolvers)?;
+    // Convert `Value` to a graph and add all remote nodes reachable from the root
+    let schema_graph = build_schema_graph(schema, root, resolvers)?;
     // Each JSON Schema is a set of keywords that may contain nested sub-schemas. As all of nodes
     // are ordered by the BFS traversal order, we can address each schema by a range of indexes:
-    //   * Create nodes with the same structure as the adjacency list but put corresponding
+    //   * Create nodes with the same structure as the schema graph but put corresponding
     //     `Some(Keyword)` instances at places containing valid JSON Schema keywords and fill
     //     everything else with `None`.
     //   * Convert edges, so they point to ranges of nodes
-    let range_graph = RangeGraph::try_from(&adjacency_list)?;
+    let range_graph = RangeGraph::try_from(&schema_graph)?;
     // Remove empty nodes and adjust all indexes
     Ok(range_graph.compress())
 }
 
-#[derive(Debug)]
-pub(crate) struct AdjacencyList<'s> {
-    pub(crate) nodes: Vec<&'s Value>,
-    pub(crate) edges: Vec<Vec<Edge>>,
-    visited: VisitedMap,
+/// A graph of JSON values connected by `$ref`-aware edges, built by a BFS traversal
+/// starting from the schema root. Identical subschemas (by pointer) are deduplicated
+/// into a single node, so `petgraph`'s traversal and `tarjan_scc` can be reused for
+/// every later analysis instead of hand-rolled `VecDeque` walks.
+pub(crate) type SchemaGraph<'s> = Graph<&'s Value, EdgeLabel, Directed>;
+
+/// Resolve a `$ref` value through lexical scoping: absolute references look up the
+/// resolver registered for their location, relative ones are folded against the
+/// current scope's `$id` folders.
+fn resolve_lexical_ref<'s>(
+    scope: &Scope<'s>,
+    resolvers: &'s HashMap<&str, Resolver>,
+    reference: &str,
+) -> Result<(Scope<'s>, &'s Value)> {
+    let (mut next_scope, resolved) = match Reference::try_from(reference)? {
+        Reference::Absolute(location) => {
+            if let Some(resolver) = resolvers.get(location.as_str()) {
+                let (folders, resolved) = resolver.resolve(reference)?;
+                (Scope::with_folders(resolver, folders), resolved)
+            } else {
+                let (_, resolved) = scope.resolver.resolve(reference)?;
+                (scope.clone(), resolved)
+            }
+        }
+        Reference::Relative(location) => {
+            let mut resolver = scope.resolver;
+            if !is_local(location) {
+                let location = scope.build_url(resolver.scope(), location)?;
+                if !resolver.contains(location.as_str()) {
+                    resolver = resolvers.get(location.as_str()).expect("Unknown reference");
+                }
+            };
+            let (folders, resolved) = resolver.resolve(location)?;
+            (Scope::with_folders(resolver, folders), resolved)
+        }
+    };
+    // Following a reference changes the lexical location, not the runtime chain of
+    // enclosing schemas, so the dynamic anchors seen so far stay in scope.
+    next_scope.dynamic_anchors = scope.dynamic_anchors.clone();
+    Ok((next_scope, resolved))
 }
 
-impl<'s> AdjacencyList<'s> {
-    fn new(
-        schema: &'s Value,
-        root: &'s Resolver,
-        resolvers: &'s HashMap<&str, Resolver>,
-    ) -> Result<Self> {
-        let mut output = AdjacencyList::empty();
-        // This is a Breadth-First-Search routine
-        let mut queue = VecDeque::new();
-        queue.push_back((Scope::new(root), NodeId(0), EdgeLabel::Index(0), schema));
-        while let Some((mut scope, parent_id, label, node)) = queue.pop_front() {
-            let slot = output.push(parent_id, label, node);
-            if slot.is_new() {
-                match node {
-                    Value::Object(object) => {
-                        scope.track_folder(object);
-                        // FIXME: track schema / non schema properly. Maybe extend scope?
-                        for (key, value) in object {
-                            if key == "$ref" {
-                                if let Value::String(reference) = value {
-                                    match Reference::try_from(reference.as_str())? {
-                                        Reference::Absolute(location) => {
-                                            if let Some(resolver) = resolvers.get(location.as_str())
-                                            {
-                                                let (folders, resolved) =
-                                                    resolver.resolve(reference)?;
-                                                queue.push_back((
-                                                    Scope::with_folders(resolver, folders),
-                                                    slot.id,
-                                                    key.into(),
-                                                    resolved,
-                                                ));
-                                            } else {
-                                                let (_, resolved) =
-                                                    scope.resolver.resolve(reference)?;
-                                                queue.push_back((
-                                                    scope.clone(),
-                                                    slot.id,
-                                                    key.into(),
-                                                    resolved,
-                                                ));
-                                            }
-                                        }
-                                        Reference::Relative(location) => {
-                                            let mut resolver = scope.resolver;
-                                            if !is_local(location) {
-                                                let location =
-                                                    scope.build_url(resolver.scope(), location)?;
-                                                if !resolver.contains(location.as_str()) {
-                                                    resolver = resolvers
-                                                        .get(location.as_str())
-                                                        .expect("Unknown reference");
-                                                }
-                                            };
-                                            let (folders, resolved) = resolver.resolve(location)?;
-                                            queue.push_back((
-                                                Scope::with_folders(resolver, folders),
-                                                slot.id,
-                                                key.into(),
-                                                resolved,
-                                            ));
-                                        }
-                                    };
+/// Build the [`SchemaGraph`] for `schema` via a Breadth-First-Search, deduplicating
+/// identical subschemas (by pointer) into a single node.
+fn build_schema_graph<'s>(
+    schema: &'s Value,
+    root: &'s Resolver,
+    resolvers: &'s HashMap<&str, Resolver>,
+) -> Result<SchemaGraph<'s>> {
+    let mut graph: SchemaGraph = Graph::new();
+    // For simpler BFS implementation we put a dummy node in the beginning
+    // This way we can assume there is always a parent node, even for the schema root
+    let root_id = graph.add_node(&Value::Null);
+    let mut visited: HashMap<*const Value, NodeIndex> = HashMap::new();
+
+    let mut queue = VecDeque::new();
+    queue.push_back((Scope::new(root), root_id, EdgeLabel::Index(0), schema));
+    while let Some((mut scope, parent_id, label, node)) = queue.pop_front() {
+        let (node_id, is_new) = match visited.entry(node as *const Value) {
+            Entry::Occupied(entry) => (*entry.get(), false),
+            Entry::Vacant(entry) => {
+                let node_id = graph.add_node(node);
+                entry.insert(node_id);
+                (node_id, true)
+            }
+        };
+        graph.add_edge(parent_id, node_id, label);
+        if is_new {
+            match node {
+                Value::Object(object) => {
+                    scope.track_folder(object, node_id);
+                    // FIXME: track schema / non schema properly. Maybe extend scope?
+                    for (key, value) in object {
+                        if key == "$ref" {
+                            if let Value::String(reference) = value {
+                                let (next_scope, resolved) =
+                                    resolve_lexical_ref(&scope, resolvers, reference)?;
+                                queue.push_back((next_scope, node_id, key.into(), resolved));
+                            }
+                        } else if key == "$dynamicRef" || key == "$recursiveRef" {
+                            if let Value::String(reference) = value {
+                                if let Some(target_id) = scope.resolve_dynamic(reference) {
+                                    // The runtime chain of enclosing schemas already has a
+                                    // matching `$dynamicAnchor`/`$recursiveAnchor` - use it.
+                                    let resolved = *graph
+                                        .node_weight(target_id)
+                                        .expect("a dynamic anchor always points at a visited node");
+                                    queue.push_back((scope.clone(), node_id, key.into(), resolved));
+                                } else {
+                                    // No enclosing dynamic scope matched the fragment, fall
+                                    // back to the ordinary lexical `$ref` resolution.
+                                    let (next_scope, resolved) =
+                                        resolve_lexical_ref(&scope, resolvers, reference)?;
+                                    queue.push_back((next_scope, node_id, key.into(), resolved));
                                 }
-                            } else {
-                                queue.push_back((scope.clone(), slot.id, key.into(), value));
                             }
+                        } else {
+                            queue.push_back((scope.clone(), node_id, key.into(), value));
                         }
                     }
-                    Value::Array(items) => {
-                        for (idx, item) in items.iter().enumerate() {
-                            queue.push_back((scope.clone(), slot.id, idx.into(), item));
-                        }
+                }
+                Value::Array(items) => {
+                    for (idx, item) in items.iter().enumerate() {
+                        queue.push_back((scope.clone(), node_id, idx.into(), item));
                     }
-                    _ => {}
                 }
+                _ => {}
             }
         }
-        Ok(output)
     }
+    Ok(graph)
+}
 
-    /// Create an empty adjacency list.
-    fn empty() -> Self {
-        Self {
-            // For simpler BFS implementation we put a dummy node in the beginning
-            // This way we can assume there is always a parent node, even for the schema root
-            nodes: vec![&Value::Null],
-            edges: vec![vec![]],
-            visited: VisitedMap::new(),
-        }
-    }
+/// The contiguous range of node indices spanned by `node`'s children. Walks `node`'s
+/// outgoing edges directly and folds over every target instead of assuming the first
+/// and last edge bound the range, since `petgraph` does not guarantee edge order.
+fn range_of(graph: &SchemaGraph, node: NodeIndex) -> Range<usize> {
+    let mut children = graph.edges(node).map(|edge| edge.target().index());
+    let Some(first) = children.next() else {
+        return 0..0;
+    };
+    let (min, max) = children.fold((first, first), |(min, max), target| {
+        (min.min(target), max.max(target))
+    });
+    min..max + 1
+}
 
-    /// Push a new node & an edge to it.
-    fn push(&mut self, parent_id: NodeId, label: EdgeLabel, node: &'s Value) -> NodeSlot {
-        let slot = match self.visited.entry(node) {
-            Entry::Occupied(entry) => NodeSlot::seen(*entry.get()),
-            Entry::Vacant(entry) => {
-                // Insert a new node & empty edges for it
-                let node_id = NodeId(self.nodes.len());
-                self.nodes.push(node);
-                self.edges.push(vec![]);
-                entry.insert(node_id);
-                NodeSlot::new(node_id)
-            }
-        };
-        // Insert a new edge from `parent_id` to this node
-        self.edges[parent_id.0].push(Edge::new(label, slot.id));
-        slot
-    }
+/// Like `range_of`, but skips one level down: used for `properties`/`allOf`, whose
+/// immediate children are per-key/per-item schema-object wrappers (reached via an
+/// arbitrary property name or array index, never a recognized keyword) that
+/// `compress()` always prunes. Spanning those wrappers' own children instead keeps
+/// the range pointing at nodes that actually survive compaction. This relies on the
+/// same BFS-contiguity guarantee as `range_of`: each wrapper's children are enqueued
+/// back-to-back as the wrappers themselves are dequeued in order, so the union of
+/// their children is one contiguous range.
+fn flattened_range_of(graph: &SchemaGraph, node: NodeIndex) -> Range<usize> {
+    let mut grandchildren = graph
+        .edges(node)
+        .flat_map(|edge| graph.edges(edge.target()).map(|e| e.target().index()));
+    let Some(first) = grandchildren.next() else {
+        return 0..0;
+    };
+    let (min, max) = grandchildren.fold((first, first), |(min, max), target| {
+        (min.min(target), max.max(target))
+    });
+    min..max + 1
+}
 
-    pub(crate) fn range_of(&self, target_id: usize) -> Range<usize> {
-        let (start, end) = match self.edges[target_id].as_slice() {
-            // Node has no edges
-            [] => return 0..0,
-            [edge] => (edge, edge),
-            [start, .., end] => (start, end),
-        };
-        // We use non-inclusive ranges, but edges point to precise indexes, hence add 1
-        start.target.value()..end.target.value() + 1
+/// Find every node that participates in a `$ref`/`$dynamicRef` cycle, using
+/// `petgraph`'s `tarjan_scc`. A node is recursive if its strongly connected component
+/// has more than one member, or if it has a self-loop (a reference to its own node).
+fn find_recursive_nodes(graph: &SchemaGraph) -> BitVector {
+    let mut recursive = BitVector::with_capacity(graph.node_count());
+    for component in tarjan_scc(graph) {
+        match component.as_slice() {
+            [node] => {
+                if graph.find_edge(*node, *node).is_some() {
+                    recursive.insert(node.index());
+                }
+            }
+            members => {
+                for node in members {
+                    recursive.insert(node.index());
+                }
+            }
+        }
     }
+    recursive
 }
 // TODO: What about specialization? When should it happen? RangeGraph?
 
 #[derive(Debug)]
 pub(crate) struct RangeGraph {
-    pub(crate) nodes: Vec<Option<Keyword>>,
+    pub(crate) nodes: Vec<Option<NodeValue>>,
     pub(crate) edges: Vec<Option<RangedEdge>>,
+    /// Nodes that participate in a `$ref` cycle, keyed by node index.
+    pub(crate) recursive: BitVector,
+}
+
+/// A node's keyword, except for `properties`/`allOf`/`$ref`/`$dynamicRef`, whose
+/// child range isn't known until `compress()` has remapped it to dense,
+/// post-compaction indices - building those eagerly at `TryFrom` time, over raw
+/// pre-compaction indices, is exactly what made their ranges point at the wrong
+/// (or a pruned) node once `compress()` ran.
+#[derive(Debug)]
+pub(crate) enum NodeValue {
+    Keyword(Keyword),
+    Properties,
+    AllOf,
+    Ref { is_recursive: bool },
+    DynamicRef { is_recursive: bool },
+}
+
+impl NodeValue {
+    /// Build the final `Keyword`, plugging in the already-remapped child range for
+    /// the variants that were deferred; ignored for plain, childless keywords.
+    fn into_keyword(self, nodes: Range<usize>) -> Keyword {
+        match self {
+            NodeValue::Keyword(keyword) => keyword,
+            NodeValue::Properties => Properties::build(nodes),
+            NodeValue::AllOf => AllOf::build(nodes),
+            NodeValue::Ref { is_recursive } => Ref::build(nodes, is_recursive),
+            NodeValue::DynamicRef { is_recursive } => DynamicRef::build(nodes, is_recursive),
+        }
+    }
 }
 
 macro_rules! vec_of_nones {
@@ -373,78 +395,105 @@ macro_rules! vec_of_nones {
     };
 }
 
-impl TryFrom<&AdjacencyList<'_>> for RangeGraph {
+impl TryFrom<&SchemaGraph<'_>> for RangeGraph {
     type Error = Error;
 
-    fn try_from(input: &AdjacencyList<'_>) -> Result<Self> {
+    fn try_from(input: &SchemaGraph<'_>) -> Result<Self> {
         let mut output = RangeGraph {
-            nodes: vec_of_nones!(input.nodes.len()),
-            edges: vec_of_nones!(input.edges.len()),
+            nodes: vec_of_nones!(input.node_count()),
+            edges: vec_of_nones!(input.node_count()),
+            recursive: find_recursive_nodes(input),
         };
-        let mut visited = vec![false; input.nodes.len()];
-        let mut queue = VecDeque::new();
-        queue.push_back((NodeId(0), &input.edges[0]));
-        while let Some((node_id, node_edges)) = queue.pop_front() {
-            if visited[node_id.value()] {
+        let root = NodeIndex::new(0);
+        let mut bfs = Bfs::new(input, root);
+        while let Some(node_id) = bfs.next(input) {
+            if node_id == root {
                 continue;
             }
-            visited[node_id.value()] = true;
             // TODO: Properly track scope of schema/nonschema.
             //       Likely $ref should be schema -> schema, and others are schema -> non-schema
             // TODO: Maybe we can skip pushing edges from non-applicators? they will be no-op here,
             //       but could be skipped upfront
-            for edge in node_edges {
-                queue.push_back((edge.target, &input.edges[edge.target.value()]));
-            }
-            if !node_id.is_root() {
-                for edge in node_edges {
-                    let target_id = edge.target.value();
-                    let value = input.nodes[target_id];
-                    match edge.label.as_key() {
-                        Some("maximum") => {
-                            output.set_node(target_id, Maximum::build(value.as_u64().unwrap()));
-                        }
-                        Some("maxLength") => {
-                            output.set_node(target_id, MaxLength::build(value.as_u64().unwrap()));
-                        }
-                        Some("minProperties") => {
-                            output
-                                .set_node(target_id, MinProperties::build(value.as_u64().unwrap()));
-                        }
-                        Some("type") => {
-                            let type_value = match value.as_str().unwrap() {
-                                "array" => ValueType::Array,
-                                "boolean" => ValueType::Boolean,
-                                "integer" => ValueType::Integer,
-                                "null" => ValueType::Null,
-                                "number" => ValueType::Number,
-                                "object" => ValueType::Object,
-                                "string" => ValueType::String,
-                                _ => panic!("invalid type"),
-                            };
-                            output.set_node(target_id, Type::build(type_value));
-                        }
-                        Some("properties") => {
-                            let edges = input.range_of(target_id);
-                            output.set_node(target_id, Properties::build(edges));
-                            output.set_many_edges(&input.edges[target_id], input);
-                        }
-                        Some("items") => {
-                            // TODO: properly set edges & node
-                            output.set_node(target_id, Items::build());
-                        }
-                        Some("allOf") => {
-                            let edges = input.range_of(target_id);
-                            output.set_node(target_id, AllOf::build(edges));
-                            output.set_many_edges(&input.edges[target_id], input);
-                        }
-                        Some("$ref") => {
-                            // TODO: Inline reference
-                            let nodes = input.range_of(target_id);
-                            output.set_node(target_id, Ref::build(nodes));
-                        }
-                        _ => {}
+            for edge in input.edges(node_id) {
+                let target_id = edge.target().index();
+                let value = *input
+                    .node_weight(edge.target())
+                    .expect("an edge's target always has a node weight");
+                match edge.weight().as_key() {
+                    Some("maximum") => {
+                        output.set_node(
+                            target_id,
+                            NodeValue::Keyword(Maximum::build(value.as_u64().unwrap())),
+                        );
+                    }
+                    Some("maxLength") => {
+                        output.set_node(
+                            target_id,
+                            NodeValue::Keyword(MaxLength::build(value.as_u64().unwrap())),
+                        );
                     }
+                    Some("minProperties") => {
+                        output.set_node(
+                            target_id,
+                            NodeValue::Keyword(MinProperties::build(value.as_u64().unwrap())),
+                        );
+                    }
+                    Some("type") => {
+                        let type_value = match value.as_str().unwrap() {
+                            "array" => ValueType::Array,
+                            "boolean" => ValueType::Boolean,
+                            "integer" => ValueType::Integer,
+                            "null" => ValueType::Null,
+                            "number" => ValueType::Number,
+                            "object" => ValueType::Object,
+                            "string" => ValueType::String,
+                            _ => panic!("invalid type"),
+                        };
+                        output.set_node(target_id, NodeValue::Keyword(Type::build(type_value)));
+                    }
+                    Some("properties") => {
+                        // The child range is left for `compress()` to plug in once it has
+                        // remapped `edges[target_id]` to dense, post-compaction indices -
+                        // building `Properties` eagerly here would bake in raw indices that
+                        // `compress()` then prunes or shifts out from under it. It's computed
+                        // with `flattened_range_of`, not `range_of`: `properties`'s immediate
+                        // children are per-key wrappers that never survive compaction, so the
+                        // range has to skip straight to their own (surviving) keyword children.
+                        output.set_node(target_id, NodeValue::Properties);
+                        let own_range = flattened_range_of(input, edge.target());
+                        output.set_edge(target_id, edge.weight().clone(), own_range);
+                    }
+                    Some("items") => {
+                        // TODO: properly set edges & node
+                        output.set_node(target_id, NodeValue::Keyword(Items::build()));
+                    }
+                    Some("allOf") => {
+                        // See the `properties` arm above: `allOf`'s immediate children are
+                        // per-item wrappers, never keyword nodes, so the range must skip past
+                        // them to their children the same way.
+                        output.set_node(target_id, NodeValue::AllOf);
+                        let own_range = flattened_range_of(input, edge.target());
+                        output.set_edge(target_id, edge.weight().clone(), own_range);
+                    }
+                    Some("$ref") => {
+                        // A recursive reference is kept as a node range instead of being
+                        // inlined, since inlining it would attempt to expand the cycle forever.
+                        let is_recursive = output.recursive.contains(target_id);
+                        output.set_node(target_id, NodeValue::Ref { is_recursive });
+                        let own_range = range_of(input, edge.target());
+                        output.set_edge(target_id, edge.weight().clone(), own_range);
+                    }
+                    Some("$dynamicRef") | Some("$recursiveRef") => {
+                        // Mirrors the `$ref` arm above: a `$dynamicRef`/`$recursiveRef` that
+                        // resolves back to an enclosing schema is exactly the kind of cycle
+                        // `find_recursive_nodes` flags, and must be kept as a range instead of
+                        // being inlined forever.
+                        let is_recursive = output.recursive.contains(target_id);
+                        output.set_node(target_id, NodeValue::DynamicRef { is_recursive });
+                        let own_range = range_of(input, edge.target());
+                        output.set_edge(target_id, edge.weight().clone(), own_range);
+                    }
+                    _ => {}
                 }
             }
         }
@@ -453,20 +502,66 @@ impl TryFrom<&AdjacencyList<'_>> for RangeGraph {
 }
 
 impl RangeGraph {
-    fn set_node(&mut self, id: usize, keyword: Keyword) {
-        self.nodes[id] = Some(keyword)
+    fn set_node(&mut self, id: usize, node: NodeValue) {
+        self.nodes[id] = Some(node)
     }
     fn set_edge(&mut self, id: usize, label: EdgeLabel, nodes: Range<usize>) {
         self.edges[id] = Some(RangedEdge::new(label, nodes))
     }
-    fn set_many_edges(&mut self, edges: &[Edge], input: &AdjacencyList) {
-        for edge in edges {
-            let id = edge.target.value();
-            self.set_edge(id, edge.label.clone(), input.range_of(id));
-        }
-    }
     fn compress(self) -> CompressedRangeGraph {
-        todo!()
+        let RangeGraph { nodes, edges, .. } = self;
+        let node_count = nodes.len();
+
+        // Assign each surviving (non-`None`) node a new, dense index. `RangedEdge`
+        // ranges were built from the original, BFS-assigned indices, so the remap
+        // has to walk nodes in that same ascending order to keep sibling ranges
+        // contiguous.
+        let mut remap: Vec<Option<usize>> = vec![None; node_count];
+        let mut next_id = 0usize;
+        for (id, node) in nodes.iter().enumerate() {
+            if node.is_some() {
+                remap[id] = Some(next_id);
+                next_id += 1;
+            }
+        }
+
+        let mut new_nodes = Vec::with_capacity(next_id);
+        let mut new_edges = Vec::with_capacity(next_id);
+        for (id, (node, edge)) in nodes.into_iter().zip(edges).enumerate() {
+            let Some(_) = remap[id] else {
+                continue;
+            };
+            let node = node.expect("surviving nodes always carry a keyword");
+            let new_edge = match edge {
+                // An applicator with no children (e.g. `"properties": {}`) has nothing to remap.
+                Some(edge) if edge.nodes.is_empty() => RangedEdge::new(edge.label, 0..0),
+                Some(edge) => {
+                    let start = remap[edge.nodes.start]
+                        .expect("a surviving edge's range must point at surviving nodes");
+                    let end = remap[edge.nodes.end - 1]
+                        .expect("a surviving edge's range must point at surviving nodes");
+                    assert_eq!(
+                        end - start,
+                        edge.nodes.len() - 1,
+                        "remapped ranges must stay contiguous, as `range_of` relies on that"
+                    );
+                    RangedEdge::new(edge.label, start..end + 1)
+                }
+                // Leaf keywords (e.g. `maximum`, `type`) have no outgoing edges of their
+                // own; keep `nodes`/`edges` aligned by node index with an empty placeholder.
+                None => RangedEdge::new(EdgeLabel::Index(0), 0..0),
+            };
+            // `into_keyword` plugs the just-remapped range into `properties`/`allOf`/
+            // `$ref`/`$dynamicRef`, so they never carry the raw, pre-compaction indices
+            // `range_of` originally computed them from.
+            new_nodes.push(node.into_keyword(new_edge.nodes.clone()));
+            new_edges.push(new_edge);
+        }
+
+        CompressedRangeGraph {
+            nodes: new_nodes,
+            edges: new_edges,
+        }
     }
 }
 
@@ -476,16 +571,100 @@ pub(crate) struct CompressedRangeGraph {
     pub(crate) edges: Vec<RangedEdge>,
 }
 
+/// Structural coupling metrics for one node, as computed by [`CompressedRangeGraph::centrality`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Centrality {
+    /// How many other nodes reference this one.
+    pub(crate) in_degree: usize,
+    /// Brandes' betweenness centrality score.
+    pub(crate) betweenness: f64,
+}
+
+impl CompressedRangeGraph {
+    /// Score every surviving node by how heavily it is reused: its reference in-degree
+    /// and its betweenness centrality over the unweighted, directed range graph, so
+    /// tooling can flag over-coupled "god schemas". The result is indexed by node, in
+    /// node order; it is not sorted by score.
+    pub(crate) fn centrality(&self) -> Vec<Centrality> {
+        let node_count = self.nodes.len();
+
+        let mut in_degree = vec![0usize; node_count];
+        for edge in &self.edges {
+            for target in edge.nodes.clone() {
+                in_degree[target] += 1;
+            }
+        }
+
+        let mut betweenness = vec![0.0f64; node_count];
+        // Brandes' algorithm: accumulate the dependency of every other node on each
+        // shortest path from `source`, one BFS per source.
+        for source in 0..node_count {
+            let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); node_count];
+            let mut sigma = vec![0.0f64; node_count];
+            let mut distance = vec![-1i64; node_count];
+            sigma[source] = 1.0;
+            distance[source] = 0;
+
+            let mut order = Vec::with_capacity(node_count);
+            let mut queue = VecDeque::new();
+            queue.push_back(source);
+            while let Some(v) = queue.pop_front() {
+                order.push(v);
+                for w in self.edges[v].nodes.clone() {
+                    if distance[w] < 0 {
+                        distance[w] = distance[v] + 1;
+                        queue.push_back(w);
+                    }
+                    if distance[w] == distance[v] + 1 {
+                        sigma[w] += sigma[v];
+                        predecessors[w].push(v);
+                    }
+                }
+            }
+
+            let mut delta = vec![0.0f64; node_count];
+            for &w in order.iter().rev() {
+                for &v in &predecessors[w] {
+                    delta[v] += (sigma[v] / sigma[w]) * (1.0 + delta[w]);
+                }
+                if w != source {
+                    betweenness[w] += delta[w];
+                }
+            }
+        }
+
+        in_degree
+            .into_iter()
+            .zip(betweenness)
+            .map(|(in_degree, betweenness)| Centrality {
+                in_degree,
+                betweenness,
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum BuildScope {
     Schema,
     NonSchema,
 }
 
+/// An entry on the dynamic scope stack: an enclosing schema's `$dynamicAnchor`
+/// (named) or `$recursiveAnchor: true` (unnamed, matched by a bare `$recursiveRef: "#"`).
+#[derive(Debug, Clone)]
+struct DynamicAnchor<'s> {
+    name: Option<&'s str>,
+    node_id: NodeIndex,
+}
+
 #[derive(Clone)]
 struct Scope<'s> {
     folders: Vec<&'s str>,
     resolver: &'s Resolver<'s>,
+    /// The runtime chain of enclosing schemas that declared a dynamic anchor, outermost first -
+    /// analogous to a name resolver's stack of enclosing scopes ("ribs").
+    dynamic_anchors: Vec<DynamicAnchor<'s>>,
 }
 
 impl<'s> Scope<'s> {
@@ -493,13 +672,48 @@ impl<'s> Scope<'s> {
         Self::with_folders(resolver, vec![])
     }
     pub(crate) fn with_folders(resolver: &'s Resolver, folders: Vec<&'s str>) -> Self {
-        Self { folders, resolver }
+        Self {
+            folders,
+            resolver,
+            dynamic_anchors: vec![],
+        }
     }
-    pub(crate) fn track_folder(&mut self, object: &'s Map<String, Value>) {
+    pub(crate) fn track_folder(&mut self, object: &'s Map<String, Value>, node_id: NodeIndex) {
         // Some objects may change `$ref` behavior via the `$id` keyword
         if let Some(id) = id_of_object(object) {
             self.folders.push(id);
         }
+        match object.get("$dynamicAnchor") {
+            Some(Value::String(name)) => self.dynamic_anchors.push(DynamicAnchor {
+                name: Some(name.as_str()),
+                node_id,
+            }),
+            _ => {
+                if let Some(Value::Bool(true)) = object.get("$recursiveAnchor") {
+                    self.dynamic_anchors.push(DynamicAnchor {
+                        name: None,
+                        node_id,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Resolve a `$dynamicRef`/`$recursiveRef` fragment against the runtime chain of
+    /// enclosing schemas, scanning from the outermost entry for the first match.
+    pub(crate) fn resolve_dynamic(&self, fragment: &str) -> Option<NodeIndex> {
+        let fragment = fragment.trim_start_matches('#');
+        if fragment.is_empty() {
+            self.dynamic_anchors
+                .iter()
+                .find(|anchor| anchor.name.is_none())
+                .map(|anchor| anchor.node_id)
+        } else {
+            self.dynamic_anchors
+                .iter()
+                .find(|anchor| anchor.name == Some(fragment))
+                .map(|anchor| anchor.node_id)
+        }
     }
 
     pub(crate) fn build_url(&self, scope: &Url, reference: &str) -> Result<Url> {
@@ -519,7 +733,7 @@ mod tests {
     use super::*;
     use crate::{
         schema::resolving,
-        testing::{assert_adjacency_list, assert_compressed_graph, assert_range_graph, load_case},
+        testing::{assert_compressed_graph, assert_range_graph, assert_schema_graph, load_case},
     };
     use test_case::test_case;
 
@@ -534,6 +748,8 @@ mod tests {
     #[test_case("ref-recursive-absolute")]
     #[test_case("ref-recursive-self")]
     #[test_case("ref-recursive-between-schemas")]
+    #[test_case("dynamic-ref-recursive-anchor")]
+    #[test_case("dynamic-ref-unnamed-recursive-anchor")]
     #[test_case("ref-remote-pointer")]
     #[test_case("ref-remote-nested")]
     #[test_case("ref-remote-base-uri-change")]
@@ -544,11 +760,157 @@ mod tests {
         let schema = &load_case(name)["schema"];
         let (root, external) = resolving::resolve(schema).unwrap();
         let resolvers = resolving::build_resolvers(&external);
-        let adjacency_list = AdjacencyList::new(schema, &root, &resolvers).unwrap();
-        assert_adjacency_list(&adjacency_list);
-        let range_graph = RangeGraph::try_from(&adjacency_list).unwrap();
+        let schema_graph = build_schema_graph(schema, &root, &resolvers).unwrap();
+        assert_schema_graph(&schema_graph);
+        let range_graph = RangeGraph::try_from(&schema_graph).unwrap();
         assert_range_graph(&range_graph);
         let compressed = range_graph.compress();
         assert_compressed_graph(&compressed);
     }
+
+    #[test]
+    fn compress_keeps_every_reachable_keyword() {
+        let schema = serde_json::json!({"type": "object", "maxLength": 5});
+        let (root, external) = resolving::resolve(&schema).unwrap();
+        let resolvers = resolving::build_resolvers(&external);
+        let schema_graph = build_schema_graph(&schema, &root, &resolvers).unwrap();
+        let range_graph = RangeGraph::try_from(&schema_graph).unwrap();
+
+        let compressed = range_graph.compress();
+
+        // `type` and `maxLength` are both direct children of the schema root, which
+        // is itself a direct (non-applicator) child of the dummy BFS root - the
+        // exact shape that used to be dropped entirely by `compress()`.
+        assert_eq!(compressed.nodes.len(), 2);
+        assert_eq!(compressed.edges.len(), 2);
+    }
+
+    #[test]
+    fn compress_remaps_properties_range_around_pruned_containers() {
+        // The doc-comment example at the top of this file: `properties` has two
+        // children, each a container node (pruned by compress(), since it carries
+        // no keyword of its own) wrapping one real keyword.
+        let schema = serde_json::json!({
+            "properties": {
+                "A": {"type": "object", "maxLength": 5},
+                "B": {"type": "string"}
+            }
+        });
+        let (root, external) = resolving::resolve(&schema).unwrap();
+        let resolvers = resolving::build_resolvers(&external);
+
+        let compressed = build(&schema, &root, &resolvers).unwrap();
+
+        // `properties`, `type`(A), `maxLength`(A), `type`(B) survive; the two
+        // container nodes for "A" and "B" are pruned.
+        assert_eq!(compressed.nodes.len(), 4);
+        let properties_range = &compressed.edges[0].nodes;
+        // Every index `properties` points at must be in bounds and must not have
+        // been left pointing at a pruned container - this was silently `3..5`
+        // (out of bounds, and off by one) before the fix.
+        assert!(properties_range.end <= compressed.nodes.len());
+        for index in properties_range.clone() {
+            assert!(compressed.edges[index].nodes.start <= compressed.nodes.len());
+        }
+    }
+
+    #[test]
+    fn build_schema_graph_keeps_equal_but_distinct_subschemas_apart() {
+        let schema = serde_json::json!({
+            "properties": {
+                "a": {"type": "string"},
+                "b": {"type": "string"}
+            }
+        });
+        let shared = &schema["properties"]["a"];
+        let other = &schema["properties"]["b"];
+        let (root, external) = resolving::resolve(&schema).unwrap();
+        let resolvers = resolving::build_resolvers(&external);
+
+        let schema_graph = build_schema_graph(&schema, &root, &resolvers).unwrap();
+
+        // "a" and "b" are distinct `Value`s with equal contents, so they must stay
+        // as two separate nodes - only pointer-identical subschemas (e.g. two
+        // `$ref`s resolving to the same location) collapse into one.
+        let node_count = schema_graph
+            .node_weights()
+            .filter(|&&value| std::ptr::eq(value, shared))
+            .count();
+        assert_eq!(node_count, 1);
+        let other_count = schema_graph
+            .node_weights()
+            .filter(|&&value| std::ptr::eq(value, other))
+            .count();
+        assert_eq!(other_count, 1);
+    }
+
+    #[test]
+    fn centrality_scores_a_path_graph() {
+        // A -> B -> C: every shortest path between A and C runs through B, so B
+        // alone should pick up betweenness, while in-degree just counts incoming
+        // `RangedEdge` references.
+        let graph = CompressedRangeGraph {
+            nodes: vec![
+                Type::build(ValueType::Object),
+                Type::build(ValueType::Object),
+                Type::build(ValueType::Object),
+            ],
+            edges: vec![
+                RangedEdge::new(EdgeLabel::Index(0), 1..2),
+                RangedEdge::new(EdgeLabel::Index(0), 2..3),
+                RangedEdge::new(EdgeLabel::Index(0), 3..3),
+            ],
+        };
+
+        let centrality = graph.centrality();
+
+        assert_eq!(centrality[0].in_degree, 0);
+        assert_eq!(centrality[1].in_degree, 1);
+        assert_eq!(centrality[2].in_degree, 1);
+        assert_eq!(centrality[0].betweenness, 0.0);
+        assert_eq!(centrality[1].betweenness, 1.0);
+        assert_eq!(centrality[2].betweenness, 0.0);
+    }
+
+    #[test]
+    fn centrality_counts_fan_in_through_properties() {
+        // `properties` is the only node with outgoing `RangedEdge`s here - before
+        // `compress()` filled in `edges[target_id]` for `properties` itself (not just
+        // its grandchildren), `centrality()` saw no adjacency at all and scored every
+        // node 0.
+        let schema = serde_json::json!({
+            "properties": {
+                "a": {"type": "string"},
+                "b": {"type": "string"}
+            }
+        });
+        let (root, external) = resolving::resolve(&schema).unwrap();
+        let resolvers = resolving::build_resolvers(&external);
+
+        let compressed = build(&schema, &root, &resolvers).unwrap();
+        let centrality = compressed.centrality();
+
+        let total_in_degree: usize = centrality.iter().map(|c| c.in_degree).sum();
+        assert_eq!(total_in_degree, 2);
+    }
+
+    #[test]
+    fn find_recursive_nodes_flags_cycles_and_self_loops() {
+        let value = Value::Null;
+        let mut graph: SchemaGraph = Graph::new();
+        let isolated = graph.add_node(&value);
+        let self_loop = graph.add_node(&value);
+        let cycle_a = graph.add_node(&value);
+        let cycle_b = graph.add_node(&value);
+        graph.add_edge(self_loop, self_loop, EdgeLabel::Index(0));
+        graph.add_edge(cycle_a, cycle_b, EdgeLabel::Index(0));
+        graph.add_edge(cycle_b, cycle_a, EdgeLabel::Index(0));
+
+        let recursive = find_recursive_nodes(&graph);
+
+        assert!(!recursive.contains(isolated.index()));
+        assert!(recursive.contains(self_loop.index()));
+        assert!(recursive.contains(cycle_a.index()));
+        assert!(recursive.contains(cycle_b.index()));
+    }
 }